@@ -8,10 +8,12 @@ use ac::AutoCompartment;
 use libc::c_uint;
 use heapsize::HeapSizeOf;
 use std::char;
+use std::collections::VecDeque;
 use std::ffi;
 use std::ptr;
 use std::slice;
 use std::mem;
+use std::panic;
 use std::u32;
 use std::default::Default;
 use std::ops::{Deref, DerefMut};
@@ -19,8 +21,12 @@ use std::thread;
 use jsapi::root::*;
 use jsval;
 use glue::{CreateAutoObjectVector, CreateCallArgsFromVp, AppendToAutoObjectVector, DeleteAutoObjectVector, IsDebugBuild};
+use glue::SliceAutoObjectVector;
 use glue::{CreateAutoIdVector, SliceAutoIdVector, DestroyAutoIdVector};
 use glue::{NewCompileOptions, DeleteCompileOptions};
+use glue::{CreateAutoCheckCannotGC, DestroyAutoCheckCannotGC, GetObjectAsArrayBufferView};
+use glue::GetObjectAsArrayBuffer;
+use glue::CollectServoSizes;
 
 const DEFAULT_HEAPSIZE: u32 = 32_u32 * 1024_u32 * 1024_u32;
 
@@ -81,9 +87,135 @@ pub struct Runtime {
     cx: *mut JSContext,
 }
 
-impl Runtime {
-    /// Creates a new `JSContext`.
-    pub fn new() -> Runtime {
+/// A builder for configuring a `Runtime` before it is created.
+///
+/// By default a `RuntimeBuilder` mirrors the settings `Runtime::new()` has
+/// always used: both JITs and native RegExp are enabled, the GC's nominal
+/// heap-size threshold is unconstrained, and the native stack quotas are
+/// Gecko's defaults. Embedders that want a pure-interpreter configuration,
+/// a tighter memory cap, or different stack quotas should start from
+/// `RuntimeBuilder::new()` instead of calling `Runtime::new()` directly.
+pub struct RuntimeBuilder {
+    baseline: bool,
+    ion: bool,
+    native_regexp: bool,
+    gc_params: Vec<(JSGCParamKey, u32)>,
+    stack_quota: usize,
+    system_code_buffer: usize,
+    trusted_script_buffer: usize,
+    allocator: Option<AllocatorVTable>,
+}
+
+impl Default for RuntimeBuilder {
+    fn default() -> RuntimeBuilder {
+        RuntimeBuilder {
+            baseline: true,
+            ion: true,
+            native_regexp: true,
+            gc_params: vec![(JSGCParamKey::JSGC_MAX_BYTES, u32::MAX)],
+            stack_quota: STACK_QUOTA,
+            system_code_buffer: SYSTEM_CODE_BUFFER,
+            trusted_script_buffer: TRUSTED_SCRIPT_BUFFER,
+            allocator: None,
+        }
+    }
+}
+
+/// A custom allocation backend, installed at `Runtime` construction with
+/// `RuntimeBuilder::allocator`, so a server-style embedder can route this
+/// context's engine allocations through its own allocator (the MongoDB
+/// mozjs integration is the motivating example).
+///
+/// The vtable is installed after `JS_NewContext` returns, so the handful of
+/// allocations `JS_NewContext` itself makes aren't covered by it; nor is
+/// the process-wide parent context every `Runtime` shares, which is
+/// created once, lazily, with the default allocator. Don't rely on this
+/// for a hard "every byte went through my allocator" guarantee -- it's
+/// meant for bulk accounting/limiting, not exhaustive interposition.
+#[derive(Clone, Copy)]
+pub struct AllocatorVTable {
+    pub malloc: unsafe extern "C" fn(usize) -> *mut ::std::os::raw::c_void,
+    pub calloc: unsafe extern "C" fn(usize, usize) -> *mut ::std::os::raw::c_void,
+    pub realloc: unsafe extern "C" fn(*mut ::std::os::raw::c_void, usize) -> *mut ::std::os::raw::c_void,
+    pub free: unsafe extern "C" fn(*mut ::std::os::raw::c_void),
+}
+
+impl RuntimeBuilder {
+    /// Creates a new `RuntimeBuilder` with the same defaults `Runtime::new()`
+    /// has always used.
+    pub fn new() -> RuntimeBuilder {
+        Default::default()
+    }
+
+    /// Toggles the Baseline JIT. Enabled by default.
+    pub fn baseline(mut self, enabled: bool) -> RuntimeBuilder {
+        self.baseline = enabled;
+        self
+    }
+
+    /// Toggles the Ion JIT. Enabled by default.
+    pub fn ion(mut self, enabled: bool) -> RuntimeBuilder {
+        self.ion = enabled;
+        self
+    }
+
+    /// Toggles the native (non-interpreted) RegExp engine. Enabled by default.
+    pub fn native_regexp(mut self, enabled: bool) -> RuntimeBuilder {
+        self.native_regexp = enabled;
+        self
+    }
+
+    /// Queues a `JS_SetGCParameter` call to be made against the new context
+    /// once it is created. Callers may pass this more than once to set
+    /// several keys (e.g. `JSGC_MAX_BYTES`, `JSGC_MAX_NURSERY_BYTES`,
+    /// `JSGC_MODE`, `JSGC_HIGH_FREQUENCY_HEAP_GROWTH_MAX`,
+    /// `JSGC_LOW_FREQUENCY_HEAP_GROWTH`). Calling this replaces the default
+    /// `JSGC_MAX_BYTES` setting only if that key is passed again.
+    pub fn gc_parameter(mut self, key: JSGCParamKey, value: u32) -> RuntimeBuilder {
+        self.gc_params.push((key, value));
+        self
+    }
+
+    /// Overrides the three native stack quotas normally derived from
+    /// `STACK_QUOTA`, `SYSTEM_CODE_BUFFER`, and `TRUSTED_SCRIPT_BUFFER`.
+    /// `quota` is the overall quota; `system_code_buffer` and
+    /// `trusted_script_buffer` are subtracted from it (cumulatively) to
+    /// derive the trusted- and untrusted-script quotas, matching the
+    /// layering `JS_SetNativeStackQuota` expects.
+    pub fn stack_quota(mut self,
+                       quota: usize,
+                       system_code_buffer: usize,
+                       trusted_script_buffer: usize)
+                       -> RuntimeBuilder {
+        assert!(system_code_buffer.checked_add(trusted_script_buffer)
+                    .map_or(false, |buffers| buffers <= quota),
+                "system_code_buffer + trusted_script_buffer must not exceed quota");
+        self.stack_quota = quota;
+        self.system_code_buffer = system_code_buffer;
+        self.trusted_script_buffer = trusted_script_buffer;
+        self
+    }
+
+    /// Installs a custom allocation backend, routing this context's engine
+    /// allocations through `vtable` instead of the system allocator. See
+    /// `AllocatorVTable` for what this does and doesn't cover.
+    pub fn allocator(mut self, vtable: AllocatorVTable) -> RuntimeBuilder {
+        self.allocator = Some(vtable);
+        self
+    }
+
+    /// Caps the runtime's nominal GC heap size at `bytes`, overriding the
+    /// default of unconstrained growth. Once the cap is hit, SpiderMonkey
+    /// forces a last-ditch GC and, if that doesn't free enough, reports a
+    /// catchable JS OOM exception rather than aborting the process.
+    /// Equivalent to `gc_parameter(JSGCParamKey::JSGC_MAX_BYTES, bytes)`.
+    pub fn memory_limit(self, bytes: u32) -> RuntimeBuilder {
+        self.gc_parameter(JSGCParamKey::JSGC_MAX_BYTES, bytes)
+    }
+
+    /// Creates a new `JSContext` with the settings accumulated on this
+    /// builder.
+    pub fn build(self) -> Runtime {
         use std::cell::UnsafeCell;
         use std::sync::{Once, ONCE_INIT};
         use std::sync::atomic::{AtomicPtr, Ordering};
@@ -148,26 +280,37 @@ impl Runtime {
                                            JS_GetParentRuntime(PARENT.get()));
             assert!(!js_context.is_null());
 
-            // Unconstrain the runtime's threshold on nominal heap size, to avoid
-            // triggering GC too often if operating continuously near an arbitrary
-            // finite threshold. This leaves the maximum-JS_malloc-bytes threshold
-            // still in effect to cause periodical, and we hope hygienic,
-            // last-ditch GCs from within the GC's allocator.
-            JS_SetGCParameter(
-                js_context, JSGCParamKey::JSGC_MAX_BYTES, u32::MAX);
+            if let Some(allocator) = self.allocator {
+                JS_SetRuntimeAllocationFunctions(js_context,
+                                                 allocator.malloc,
+                                                 allocator.calloc,
+                                                 allocator.realloc,
+                                                 allocator.free);
+            }
+
+            // By default, unconstrain the runtime's threshold on nominal heap
+            // size, to avoid triggering GC too often if operating
+            // continuously near an arbitrary finite threshold. This leaves
+            // the maximum-JS_malloc-bytes threshold still in effect to cause
+            // periodical, and we hope hygienic, last-ditch GCs from within
+            // the GC's allocator. Callers may queue additional/overriding
+            // keys via `RuntimeBuilder::gc_parameter`.
+            for (key, value) in self.gc_params {
+                JS_SetGCParameter(js_context, key, value);
+            }
 
             JS_SetNativeStackQuota(
                 js_context,
-                STACK_QUOTA,
-                STACK_QUOTA - SYSTEM_CODE_BUFFER,
-                STACK_QUOTA - SYSTEM_CODE_BUFFER - TRUSTED_SCRIPT_BUFFER);
+                self.stack_quota,
+                self.stack_quota - self.system_code_buffer,
+                self.stack_quota - self.system_code_buffer - self.trusted_script_buffer);
 
             JS::InitSelfHostedCode(js_context);
 
             let opts = JS::ContextOptionsRef(js_context);
-            (*opts).set_baseline_(true);
-            (*opts).set_ion_(true);
-            (*opts).set_nativeRegExp_(true);
+            (*opts).set_baseline_(self.baseline);
+            (*opts).set_ion_(self.ion);
+            (*opts).set_nativeRegExp_(self.native_regexp);
 
             JS::SetWarningReporter(js_context, Some(report_warning));
 
@@ -178,6 +321,15 @@ impl Runtime {
             }
         }
     }
+}
+
+impl Runtime {
+    /// Creates a new `JSContext` with the default settings. Equivalent to
+    /// `RuntimeBuilder::new().build()`; use `RuntimeBuilder` directly to
+    /// customize the JITs, GC parameters, or stack quotas.
+    pub fn new() -> Runtime {
+        RuntimeBuilder::new().build()
+    }
 
     /// Returns the underlying `JSContext` object.
     pub fn cx(&self) -> *mut JSContext {
@@ -213,11 +365,99 @@ impl Runtime {
             }
         }
     }
+
+    /// Like `evaluate_script`, but on failure pulls the pending exception
+    /// off the context and returns it as a structured `JsError` instead of
+    /// discarding it.
+    pub fn evaluate_script_with_error(&self, glob: JS::HandleObject, script: &str,
+                                      filename: &str, line_num: u32,
+                                      rval: JS::MutableHandleValue)
+                                      -> Result<(), JsError> {
+        match self.evaluate_script(glob, script, filename, line_num, rval) {
+            Ok(()) => Ok(()),
+            Err(()) => unsafe { Err(JsError::from_pending_exception(self.cx())) },
+        }
+    }
+}
+
+/// A structured JS exception, captured from the context's pending exception
+/// by `evaluate_script_with_error`.
+#[derive(Clone, Debug)]
+pub struct JsError {
+    pub message: String,
+    pub filename: String,
+    pub lineno: u32,
+    pub column: u32,
+    pub stack: Option<String>,
+}
+
+impl JsError {
+    /// Pulls the pending exception off `cx` (clearing it) and builds a
+    /// `JsError` out of it. If the thrown value is an `Error` object, its
+    /// `.stack` property is read as well.
+    unsafe fn from_pending_exception(cx: *mut JSContext) -> JsError {
+        rooted!(in(cx) let mut exception = jsval::UndefinedValue());
+        if !JS_GetPendingException(cx, exception.handle_mut()) {
+            return JsError {
+                message: "unknown error (no pending exception)".to_string(),
+                filename: "none".to_string(),
+                lineno: 0,
+                column: 0,
+                stack: None,
+            };
+        }
+        JS_ClearPendingException(cx);
+
+        let mut report_fallback = (String::new(), 0, 0, String::new());
+        if exception.is_object() {
+            let report = JS_ErrorFromException(cx, exception.to_object());
+            if !report.is_null() {
+                report_fallback = error_report_parts(report);
+            }
+        }
+        let (filename, lineno, column, mut message) = report_fallback;
+        if message.is_empty() {
+            message = "uncaught exception".to_string();
+        }
+
+        let stack = if exception.is_object() {
+            rooted!(in(cx) let exc_obj = exception.to_object());
+            rooted!(in(cx) let mut stack_val = jsval::UndefinedValue());
+            let stack_name = ffi::CString::new("stack").unwrap();
+            if JS_GetProperty(cx, exc_obj.handle(), stack_name.as_ptr(), stack_val.handle_mut())
+               && stack_val.is_string() {
+                Some(js_string_to_string(cx, stack_val.to_string()))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        JsError {
+            message: message,
+            filename: filename,
+            lineno: lineno,
+            column: column,
+            stack: stack,
+        }
+    }
 }
 
 impl Drop for Runtime {
     fn drop(&mut self) {
         unsafe {
+            let private = JS_GetContextPrivate(self.cx) as *mut ContextPrivate;
+            if !private.is_null() {
+                // `JS_DestroyContext` forces a final GC/sweep on its way
+                // out, which would otherwise call back into the trampolines
+                // below with a dangling `data` pointer. Unregister them
+                // before freeing the `ContextPrivate` they point at.
+                JS_SetGCCallback(self.cx, None, ptr::null_mut());
+                JS_SetFinalizeCallback(self.cx, None);
+                JS_SetContextPrivate(self.cx, ptr::null_mut());
+                drop(Box::from_raw(private));
+            }
             JS_EndRequest(self.cx);
             JS_DestroyContext(self.cx);
         }
@@ -231,6 +471,331 @@ impl HeapSizeOf for Runtime {
     }
 }
 
+// ___________________________________________________________________________
+// GC and allocation lifecycle callbacks
+
+/// A phase of the SpiderMonkey GC lifecycle, passed to closures registered
+/// via `Runtime::on_gc`. Mirrors what `JS_SetGCCallback`/
+/// `JS_SetFinalizeCallback` actually report -- there is no per-object
+/// finalize or heap-resize notification wired up here, since no global
+/// callback carries that information (per-object finalization only happens
+/// inside a class's own `JSClassOps::finalize` hook).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcPhase {
+    /// The collector is about to begin a GC (`JSGC_BEGIN`).
+    Begin,
+    /// The collector has finished a GC; all unreachable objects have been
+    /// swept (`JSGC_END`).
+    End,
+    /// A sweep group's finalization is about to start
+    /// (`JSFINALIZE_GROUP_START`). Fires once per sweep group, not once per
+    /// `GC`.
+    SweepStart,
+    /// A sweep group's finalization has finished
+    /// (`JSFINALIZE_GROUP_END`). This does *not* mean a single object was
+    /// just finalized -- it fires once per sweep group, which can cover
+    /// many objects.
+    SweepGroupEnd,
+    /// All finalization for this collection has finished
+    /// (`JSFINALIZE_COLLECTION_END`).
+    FinalizeEnd,
+}
+
+type GcCallback = Box<Fn(GcPhase) + 'static>;
+
+/// Per-context state reachable from the `JSContext`'s private slot, shared
+/// by the optional Rust hooks a `Runtime` can install (GC lifecycle
+/// callbacks, the module resolve hook, ...). A `Runtime` lazily allocates
+/// one of these the first time such a hook is registered, and frees it in
+/// `Drop for Runtime`.
+#[derive(Default)]
+struct ContextPrivate {
+    gc_callbacks: Vec<GcCallback>,
+    module_resolve_hook: Option<ModuleResolveHook>,
+}
+
+impl ContextPrivate {
+    fn fire_gc(&self, phase: GcPhase) {
+        for callback in &self.gc_callbacks {
+            callback(phase);
+        }
+    }
+}
+
+impl Runtime {
+    /// Returns the `ContextPrivate` for this runtime, allocating and
+    /// installing it on the context's private slot if this is the first
+    /// hook being registered.
+    unsafe fn context_private(&self) -> *mut ContextPrivate {
+        let mut private = JS_GetContextPrivate(self.cx) as *mut ContextPrivate;
+        if private.is_null() {
+            private = Box::into_raw(Box::new(ContextPrivate::default()));
+            JS_SetContextPrivate(self.cx, private as *mut _);
+        }
+        private
+    }
+}
+
+unsafe extern "C" fn gc_callback_trampoline(_cx: *mut JSContext,
+                                            status: JSGCStatus,
+                                            data: *mut ::std::os::raw::c_void) {
+    let private = &*(data as *const ContextPrivate);
+    match status {
+        JSGCStatus::JSGC_BEGIN => private.fire_gc(GcPhase::Begin),
+        JSGCStatus::JSGC_END => private.fire_gc(GcPhase::End),
+    }
+}
+
+unsafe extern "C" fn finalize_callback_trampoline(_fop: *mut JSFreeOp,
+                                                  status: JSFinalizeStatus,
+                                                  _is_compartment_gc: bool,
+                                                  data: *mut ::std::os::raw::c_void) {
+    let private = &*(data as *const ContextPrivate);
+    match status {
+        JSFinalizeStatus::JSFINALIZE_GROUP_START => private.fire_gc(GcPhase::SweepStart),
+        JSFinalizeStatus::JSFINALIZE_GROUP_END => private.fire_gc(GcPhase::SweepGroupEnd),
+        JSFinalizeStatus::JSFINALIZE_COLLECTION_END => private.fire_gc(GcPhase::FinalizeEnd),
+    }
+}
+
+impl Runtime {
+    /// Registers a closure to be invoked on SpiderMonkey GC lifecycle
+    /// transitions (see `GcPhase`). Multiple closures may be registered;
+    /// they fire in registration order. The closures are boxed and stored
+    /// behind the context's private slot, wiring up `JS_SetGCCallback` and
+    /// `JS_SetFinalizeCallback` the first time this is called.
+    ///
+    /// This lets embedders drive memory-pressure heuristics, emit profiling
+    /// spans, or pump their own work between GC slices without patching this
+    /// crate.
+    pub fn on_gc<F>(&self, callback: F)
+        where F: Fn(GcPhase) + 'static
+    {
+        unsafe {
+            let private = self.context_private();
+            if (*private).gc_callbacks.is_empty() {
+                JS_SetGCCallback(self.cx, Some(gc_callback_trampoline), private as *mut _);
+                JS_SetFinalizeCallback(self.cx, Some(finalize_callback_trampoline));
+            }
+            (*private).gc_callbacks.push(Box::new(callback));
+        }
+    }
+}
+
+// ___________________________________________________________________________
+// ES module loading and evaluation
+
+type ModuleResolveHook = Box<Fn(&str, *mut JSObject) -> Result<*mut JSObject, ()> + 'static>;
+
+/// Reads the UTF-16 characters of `s` out into a Rust `String`.
+unsafe fn js_string_to_string(cx: *mut JSContext, s: *mut JSString) -> String {
+    let mut len = 0;
+    let chars = JS_GetTwoByteStringCharsAndLength(cx, ptr::null_mut(), s, &mut len);
+    String::from_utf16_lossy(slice::from_raw_parts(chars, len))
+}
+
+unsafe extern "C" fn module_resolve_trampoline(cx: *mut JSContext,
+                                               referencing_module: JS::HandleValue,
+                                               specifier: JS::HandleString)
+                                               -> *mut JSObject {
+    let private = JS_GetContextPrivate(cx) as *mut ContextPrivate;
+    if private.is_null() {
+        return ptr::null_mut();
+    }
+    let hook = match (*private).module_resolve_hook {
+        Some(ref hook) => hook,
+        None => return ptr::null_mut(),
+    };
+    let specifier = js_string_to_string(cx, specifier.get());
+    let referencing_module = referencing_module.get().to_object();
+    match hook(&specifier, referencing_module) {
+        Ok(module) => module,
+        Err(()) => ptr::null_mut(),
+    }
+}
+
+impl Runtime {
+    /// Installs the module resolve hook used by `import` statements
+    /// evaluated in modules compiled with `compile_module`. The hook is
+    /// given the import specifier and the referencing module, and should
+    /// return the resolved module object, built with whatever loader
+    /// (filesystem, bundler, virtual modules) the embedder wants.
+    pub fn set_module_resolve_hook<F>(&self, hook: F)
+        where F: Fn(&str, *mut JSObject) -> Result<*mut JSObject, ()> + 'static
+    {
+        unsafe {
+            let private = self.context_private();
+            (*private).module_resolve_hook = Some(Box::new(hook));
+            JS::SetModuleResolveHook(self.cx, Some(module_resolve_trampoline));
+        }
+    }
+
+    /// Compiles `source` as an ECMAScript module (as opposed to a classic
+    /// script; see `evaluate_script`) and roots the resulting module record
+    /// into `rval`. The module still needs `module_instantiate` and
+    /// `module_evaluate` before its top-level code runs.
+    pub fn compile_module(&self, glob: JS::HandleObject, source: &str, filename: &str,
+                          rval: JS::MutableHandleObject)
+                          -> Result<(), ()> {
+        let source_utf16: Vec<u16> = source.encode_utf16().collect();
+        let filename_cstr = ffi::CString::new(filename.as_bytes()).unwrap();
+        unsafe {
+            let _ac = AutoCompartment::with_obj(self.cx(), glob.get());
+            let options = CompileOptionsWrapper::new(self.cx(), filename_cstr.as_ptr(), 1);
+
+            let module = JS::CompileModule(self.cx(),
+                                           options.ptr,
+                                           source_utf16.as_ptr(),
+                                           source_utf16.len());
+            if module.is_null() {
+                Err(())
+            } else {
+                rval.set(module);
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves the module's imports (via the hook installed with
+    /// `set_module_resolve_hook`) and links its bindings. Must be called
+    /// before `module_evaluate`.
+    pub fn module_instantiate(&self, module: JS::HandleObject) -> Result<(), ()> {
+        unsafe {
+            JS::ModuleInstantiate(self.cx(), module).to_result()
+        }
+    }
+
+    /// Runs a module's top-level code. The module must already have been
+    /// instantiated with `module_instantiate`.
+    pub fn module_evaluate(&self, module: JS::HandleObject) -> Result<(), ()> {
+        unsafe {
+            JS::ModuleEvaluate(self.cx(), module).to_result()
+        }
+    }
+}
+
+// ___________________________________________________________________________
+// Off-thread (background) script compilation
+
+/// Below this source length, `compile_offthread` compiles synchronously
+/// instead of paying the cost of a background-thread handoff.
+const OFFTHREAD_LENGTH_THRESHOLD: usize = 100 * 1024;
+
+/// A handle to a script submitted to `compile_offthread`. Pass this to
+/// `finish_offthread`, on the runtime's own thread, to retrieve the
+/// compiled `JSScript`.
+pub enum CompileToken {
+    /// The source was below `OFFTHREAD_LENGTH_THRESHOLD` (or SpiderMonkey
+    /// declined to offload it), and was compiled synchronously already.
+    Ready(*mut JSScript),
+    /// Compilation was handed off to a SpiderMonkey background thread.
+    Pending(*mut ::std::os::raw::c_void),
+}
+
+/// `data` is the `on_complete` callback, boxed twice over so that the thin
+/// pointer handed to SpiderMonkey can be reconstituted into the fat
+/// `Box<Fn()>` it really is. It is passed straight through by SpiderMonkey,
+/// so there is no window where this trampoline can fire before the callback
+/// it needs is reachable.
+unsafe extern "C" fn offthread_compile_done(_token: *mut ::std::os::raw::c_void,
+                                            data: *mut ::std::os::raw::c_void) {
+    if !data.is_null() {
+        let callback = Box::from_raw(data as *mut Box<Fn() + 'static>);
+        callback();
+    }
+}
+
+impl Runtime {
+    /// Compiles `source` on a SpiderMonkey background thread when it is
+    /// large enough to be worth it (per `JS::CanCompileOffThread`),
+    /// otherwise falls back to compiling it synchronously right away.
+    /// `on_complete`, if given, fires once background compilation finishes;
+    /// it is never invoked for sources that took the synchronous path.
+    /// SpiderMonkey calls it from its own helper thread, not this one, so
+    /// it must be `Send`. Either way, call `finish_offthread` on the
+    /// runtime's own thread with the returned token to retrieve the
+    /// compiled script.
+    pub fn compile_offthread<F>(&self, glob: JS::HandleObject, source: &str, filename: &str,
+                               line_num: u32, on_complete: Option<F>)
+                               -> Result<CompileToken, ()>
+        where F: Fn() + Send + 'static
+    {
+        let source_utf16: Vec<u16> = source.encode_utf16().collect();
+        let filename_cstr = ffi::CString::new(filename.as_bytes()).unwrap();
+        unsafe {
+            let _ac = AutoCompartment::with_obj(self.cx(), glob.get());
+            let options = CompileOptionsWrapper::new(self.cx(), filename_cstr.as_ptr(), line_num);
+
+            let can_offthread = source_utf16.len() >= OFFTHREAD_LENGTH_THRESHOLD &&
+                JS::CanCompileOffThread(self.cx(), options.ptr, source_utf16.len());
+
+            if !can_offthread {
+                let script = JS::Compile(self.cx(), options.ptr,
+                                         source_utf16.as_ptr(), source_utf16.len());
+                return if script.is_null() {
+                    Err(())
+                } else {
+                    Ok(CompileToken::Ready(script))
+                };
+            }
+
+            let data = match on_complete {
+                Some(on_complete) => {
+                    Box::into_raw(Box::new(Box::new(on_complete) as Box<Fn() + 'static>)) as
+                        *mut ::std::os::raw::c_void
+                }
+                None => ptr::null_mut(),
+            };
+
+            let token = JS::CompileOffThread(self.cx(),
+                                             options.ptr,
+                                             source_utf16.as_ptr(),
+                                             source_utf16.len(),
+                                             Some(offthread_compile_done),
+                                             data);
+            if token.is_null() {
+                if !data.is_null() {
+                    // SpiderMonkey never took ownership of `data`; reclaim it
+                    // so the callback isn't leaked.
+                    drop(Box::from_raw(data as *mut Box<Fn() + 'static>));
+                }
+                return Err(());
+            }
+            Ok(CompileToken::Pending(token))
+        }
+    }
+
+    /// Finalizes a script compiled with `compile_offthread`. Must be called
+    /// on the runtime's own thread. For a `CompileToken::Pending` token this
+    /// blocks until the background compile finishes.
+    pub fn finish_offthread(&self, token: CompileToken, rval: JS::MutableHandle<*mut JSScript>)
+                            -> Result<(), ()> {
+        unsafe {
+            let script = match token {
+                CompileToken::Ready(script) => script,
+                CompileToken::Pending(token) => JS::FinishOffThreadScript(self.cx(), token),
+            };
+            if script.is_null() {
+                Err(())
+            } else {
+                rval.set(script);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs a script previously produced by `finish_offthread` (or obtained
+    /// any other way) against `glob`.
+    pub fn run_script(&self, glob: JS::HandleObject, script: JS::Handle<*mut JSScript>,
+                      rval: JS::MutableHandleValue)
+                      -> Result<(), ()> {
+        unsafe {
+            let _ac = AutoCompartment::with_obj(self.cx(), glob.get());
+            JS_ExecuteScript(self.cx(), script, rval).to_result()
+        }
+    }
+}
+
 // ___________________________________________________________________________
 // Rooting API for standard JS things
 
@@ -691,6 +1256,18 @@ impl Drop for AutoObjectVectorWrapper {
     }
 }
 
+impl Deref for AutoObjectVectorWrapper {
+    type Target = [*mut JSObject];
+
+    fn deref(&self) -> &[*mut JSObject] {
+        unsafe {
+            let mut length = 0;
+            let pointer = SliceAutoObjectVector(self.ptr as *const _, &mut length);
+            slice::from_raw_parts(pointer, length)
+        }
+    }
+}
+
 pub struct CompileOptionsWrapper {
     pub ptr: *mut JS::ReadOnlyCompileOptions
 }
@@ -813,11 +1390,102 @@ pub unsafe fn ToString(cx: *mut JSContext, v: JS::HandleValue) -> *mut JSString
     js::ToStringSlow(cx, v)
 }
 
-pub unsafe extern fn report_warning(_cx: *mut JSContext, report: *mut JSErrorReport) {
-    fn latin1_to_string(bytes: &[u8]) -> String {
-        bytes.iter().map(|c| char::from_u32(*c as u32).unwrap()).collect()
+// ___________________________________________________________________________
+// ArrayBuffer and TypedArray wrappers
+
+/// Creates a new, zeroed `ArrayBuffer` of `len` bytes.
+pub unsafe fn new_array_buffer(cx: *mut JSContext, len: usize) -> *mut JSObject {
+    JS_NewArrayBuffer(cx, len as u32)
+}
+
+/// Creates a new `Uint8Array` of `data.len()` bytes and copies `data` into
+/// its backing store.
+pub unsafe fn new_uint8_array(cx: *mut JSContext, data: &[u8]) -> *mut JSObject {
+    let array = JS_NewUint8Array(cx, data.len() as u32);
+    if array.is_null() {
+        return array;
+    }
+
+    rooted!(in(cx) let rooted_array = array);
+    let nogc = AutoCheckCannotGC::new();
+    if let Some(dest) = array_buffer_view_data_mut(rooted_array.get(), &nogc) {
+        dest.copy_from_slice(data);
+    }
+    array
+}
+
+/// A token proving that, for its lifetime, SpiderMonkey will not move or
+/// free the backing store of an `ArrayBuffer`/typed array, mirroring
+/// `JS::AutoCheckCannotGC`. A borrow returned by `array_buffer_view_data`/
+/// `array_buffer_view_data_mut` cannot outlive the guard that produced it,
+/// so it cannot outlive a GC.
+pub struct AutoCheckCannotGC {
+    ptr: *mut JS::AutoCheckCannotGC,
+}
+
+impl AutoCheckCannotGC {
+    pub fn new() -> AutoCheckCannotGC {
+        unsafe {
+            AutoCheckCannotGC { ptr: CreateAutoCheckCannotGC() }
+        }
+    }
+}
+
+impl Drop for AutoCheckCannotGC {
+    fn drop(&mut self) {
+        unsafe { DestroyAutoCheckCannotGC(self.ptr) }
+    }
+}
+
+/// Returns a zero-copy view into the backing store of `obj`, which must be
+/// an `ArrayBuffer` or a typed array view over one (plain `ArrayBuffer`s,
+/// such as those returned by `new_array_buffer`, are handled by falling
+/// back to `GetObjectAsArrayBuffer` when `obj` is not itself a view). A
+/// view over a `SharedArrayBuffer` is rejected: another agent can be
+/// mutating that backing store concurrently, which this safe `&[u8]`/
+/// `&mut [u8]` API cannot account for. `nogc` guards the returned borrow
+/// against a GC moving or freeing the backing store out from underneath
+/// it.
+pub unsafe fn array_buffer_view_data<'a>(obj: *mut JSObject, nogc: &'a AutoCheckCannotGC)
+                                         -> Option<&'a [u8]> {
+    let _ = nogc;
+    let mut len = 0;
+    let mut is_shared = false;
+    let mut data = ptr::null_mut();
+    if !GetObjectAsArrayBufferView(obj, &mut len, &mut is_shared, &mut data) &&
+       !GetObjectAsArrayBuffer(obj, &mut len, &mut data) {
+        return None;
+    }
+    if is_shared || data.is_null() {
+        return None;
+    }
+    Some(slice::from_raw_parts(data, len as usize))
+}
+
+/// Like `array_buffer_view_data`, but returns a mutable view.
+pub unsafe fn array_buffer_view_data_mut<'a>(obj: *mut JSObject, nogc: &'a AutoCheckCannotGC)
+                                             -> Option<&'a mut [u8]> {
+    let _ = nogc;
+    let mut len = 0;
+    let mut is_shared = false;
+    let mut data = ptr::null_mut();
+    if !GetObjectAsArrayBufferView(obj, &mut len, &mut is_shared, &mut data) &&
+       !GetObjectAsArrayBuffer(obj, &mut len, &mut data) {
+        return None;
+    }
+    if is_shared || data.is_null() {
+        return None;
     }
+    Some(slice::from_raw_parts_mut(data, len as usize))
+}
+
+fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|c| char::from_u32(*c as u32).unwrap()).collect()
+}
 
+/// Pulls the filename, line, column, and message out of a `JSErrorReport`,
+/// shared by `report_warning` and `JsError::from_pending_exception`.
+unsafe fn error_report_parts(report: *mut JSErrorReport) -> (String, u32, u32, String) {
     let fnptr = (*report)._base.filename;
     let fname = if !fnptr.is_null() {
         let c_str = ffi::CStr::from_ptr(fnptr);
@@ -834,6 +1502,11 @@ pub unsafe extern fn report_warning(_cx: *mut JSContext, report: *mut JSErrorRep
     let msg_slice = slice::from_raw_parts(msg_ptr, msg_len);
     let msg = String::from_utf16_lossy(msg_slice);
 
+    (fname, lineno, column, msg)
+}
+
+pub unsafe extern fn report_warning(_cx: *mut JSContext, report: *mut JSErrorReport) {
+    let (fname, lineno, column, msg) = error_report_parts(report);
     warn!("Warning at {}:{}:{}: {}\n", fname, lineno, column, msg);
 }
 
@@ -878,6 +1551,56 @@ impl Deref for IdVector {
     }
 }
 
+// ___________________________________________________________________________
+// Panic-guarding native callbacks
+
+/// Runs `task` inside `catch_unwind`. If it panics, reports a pending JS
+/// exception on `cx` describing the panic and returns `default` instead of
+/// letting the panic unwind across the C++ JSAPI boundary, which is
+/// undefined behavior. Mirrors the `wrap_panic` pattern the generated DOM
+/// bindings use to keep native JS callbacks panic-safe.
+pub fn wrap_panic<F, T>(cx: *mut JSContext, default: T, task: F) -> T
+    where F: FnOnce() -> T
+{
+    match panic::catch_unwind(panic::AssertUnwindSafe(task)) {
+        Ok(result) => result,
+        Err(error) => {
+            let message = error.downcast_ref::<String>()
+                                .cloned()
+                                .or_else(|| error.downcast_ref::<&'static str>().map(|s| s.to_string()))
+                                .unwrap_or_else(|| "Rust native callback panicked".to_string());
+            unsafe {
+                let message_cstr = ffi::CString::new(message)
+                    .unwrap_or_else(|_| ffi::CString::new("Rust native callback panicked").unwrap());
+                JS_ReportErrorASCII(cx, message_cstr.as_ptr());
+            }
+            default
+        }
+    }
+}
+
+/// Defines a `JSNative`-shaped `extern "C" fn` named `$name` whose body is
+/// automatically panic-guarded with `wrap_panic`, so a panic inside `$body`
+/// reports a pending exception and returns `false` rather than unwinding
+/// across the FFI boundary. `$body` is a closure taking `(cx, args)` where
+/// `args` is the `JS::CallArgs` built from `vp`. Methods defined this way
+/// and registered through `define_methods` are panic-safe without further
+/// effort from the caller.
+#[macro_export]
+macro_rules! native_fn {
+    ($name:ident, $body:expr) => {
+        pub unsafe extern "C" fn $name(cx: *mut $crate::jsapi::root::JSContext,
+                                       argc: ::libc::c_uint,
+                                       vp: *mut $crate::jsapi::root::JS::Value)
+                                       -> bool {
+            $crate::rust::wrap_panic(cx, false, || {
+                let args = $crate::jsapi::root::JS::CallArgs::from_vp(vp, argc);
+                $body(cx, args)
+            })
+        }
+    }
+}
+
 /// Defines methods on `obj`. The last entry of `methods` must contain zeroed
 /// memory.
 ///
@@ -942,6 +1665,203 @@ pub unsafe fn define_properties(cx: *mut JSContext, obj: JS::HandleObject,
     JS_DefineProperties(cx, obj, properties.as_ptr()).to_result()
 }
 
+// ___________________________________________________________________________
+// Declarative builders for JSFunctionSpec/JSPropertySpec arrays
+
+/// Declares a `&'static [JSFunctionSpec]` suitable for `define_methods`,
+/// appending the terminating zeroed sentinel entry automatically so callers
+/// can't forget it. Each entry is `"name", nargs, native_fn, flags`, where
+/// `native_fn` is an `extern "C" fn` matching `JSNative` -- typically one
+/// defined with `native_fn!` so it is panic-safe as well.
+#[macro_export]
+macro_rules! js_function_specs {
+    ($($name:expr, $nargs:expr, $call:expr, $flags:expr);* $(;)*) => {
+        &[
+            $(
+                $crate::jsapi::root::JSFunctionSpec {
+                    name: concat!($name, "\0") as *const str as *const u8 as *const _,
+                    call: $crate::jsapi::root::JSNativeWrapper {
+                        op: Some($call),
+                        info: ::std::ptr::null(),
+                    },
+                    nargs: $nargs,
+                    flags: $flags,
+                    selfHostedName: ::std::ptr::null(),
+                },
+            )*
+            $crate::jsapi::root::JSFunctionSpec {
+                name: ::std::ptr::null(),
+                call: $crate::jsapi::root::JSNativeWrapper { op: None, info: ::std::ptr::null() },
+                nargs: 0,
+                flags: 0,
+                selfHostedName: ::std::ptr::null(),
+            },
+        ]
+    }
+}
+
+/// Declares a `&'static [JSPropertySpec]` suitable for `define_properties`,
+/// appending the terminating zeroed sentinel entry automatically. Each
+/// entry is `"name", flags, getter, setter`, where `getter` and `setter`
+/// are both `Option<fn>` expressions -- pass `Some(your_fn)` for each that
+/// applies and `None` for a read-only property's setter -- following the
+/// `JSNative` getter/setter layout PropertySpec.h describes for accessor
+/// properties.
+#[macro_export]
+macro_rules! js_property_specs {
+    ($($name:expr, $flags:expr, $getter:expr, $setter:expr);* $(;)*) => {
+        &[
+            $(
+                $crate::jsapi::root::JSPropertySpec {
+                    name: concat!($name, "\0") as *const str as *const u8 as *const _,
+                    flags: $flags,
+                    getter: $crate::jsapi::root::JSNativeWrapper {
+                        op: $getter,
+                        info: ::std::ptr::null(),
+                    },
+                    setter: $crate::jsapi::root::JSNativeWrapper {
+                        op: $setter,
+                        info: ::std::ptr::null(),
+                    },
+                },
+            )*
+            $crate::jsapi::root::JSPropertySpec {
+                name: ::std::ptr::null(),
+                flags: 0,
+                getter: $crate::jsapi::root::JSNativeWrapper { op: None, info: ::std::ptr::null() },
+                setter: $crate::jsapi::root::JSNativeWrapper { op: None, info: ::std::ptr::null() },
+            },
+        ]
+    }
+}
+
+// ___________________________________________________________________________
+// Custom JSClass construction
+
+/// A builder for a custom `JSClass`/`JSClassOps` pair. `SIMPLE_GLOBAL_CLASS`
+/// below is the one class this crate predefines, for test globals; real
+/// embedders that need their own object classes (with custom `finalize`,
+/// `trace`, `resolve`/`mayResolve`/`enumerate`, or the `call`/`construct`/
+/// `hasInstance` hooks used for constructible or callable objects) should
+/// start from `ClassBuilder::new` instead.
+///
+/// `build` leaks the class name, the `JSClassOps`, and the `JSClass` itself
+/// as `'static`, since upstream split `JSClassOps` out into a separately
+/// `cOps`-pointed struct and the class needs to remain valid for as long as
+/// any object of it is alive -- in practice, the lifetime of the `Runtime`.
+pub struct ClassBuilder {
+    name: ffi::CString,
+    flags: u32,
+    reserved_slots: u32,
+    ops: JSClassOps,
+}
+
+impl ClassBuilder {
+    pub fn new(name: &str) -> ClassBuilder {
+        ClassBuilder {
+            name: ffi::CString::new(name).unwrap(),
+            flags: 0,
+            reserved_slots: 0,
+            ops: JSClassOps {
+                addProperty: None,
+                delProperty: None,
+                getProperty: None,
+                setProperty: None,
+                enumerate: None,
+                resolve: None,
+                mayResolve: None,
+                finalize: None,
+                call: None,
+                hasInstance: None,
+                construct: None,
+                trace: None,
+            },
+        }
+    }
+
+    /// Ors in additional `JSCLASS_*` flags (e.g. `JSCLASS_IS_GLOBAL`).
+    pub fn flags(mut self, flags: u32) -> ClassBuilder {
+        self.flags |= flags;
+        self
+    }
+
+    /// Reserves `count` private `JSCLASS_RESERVED_SLOTS` on instances of
+    /// this class.
+    pub fn reserved_slots(mut self, count: u32) -> ClassBuilder {
+        self.reserved_slots = count;
+        self
+    }
+
+    pub fn finalize(mut self, hook: unsafe extern "C" fn(*mut JSFreeOp, *mut JSObject))
+                    -> ClassBuilder {
+        self.ops.finalize = Some(hook);
+        self
+    }
+
+    pub fn trace(mut self, hook: unsafe extern "C" fn(*mut JSTracer, *mut JSObject))
+                -> ClassBuilder {
+        self.ops.trace = Some(hook);
+        self
+    }
+
+    pub fn resolve(mut self, hook: JSResolveOp) -> ClassBuilder {
+        self.ops.resolve = hook;
+        self
+    }
+
+    pub fn may_resolve(mut self, hook: JSMayResolveOp) -> ClassBuilder {
+        self.ops.mayResolve = hook;
+        self
+    }
+
+    pub fn enumerate(mut self, hook: JSEnumerateOp) -> ClassBuilder {
+        self.ops.enumerate = hook;
+        self
+    }
+
+    /// Installs the `[[Call]]` hook, making instances of this class
+    /// callable (the pattern `sBoringInterfaceObjectClassClassOps`'s
+    /// `ThrowingConstructor` uses to reject calls while still allowing
+    /// `new`).
+    pub fn call(mut self, hook: JSNative) -> ClassBuilder {
+        self.ops.call = hook;
+        self
+    }
+
+    /// Installs the `[[Construct]]` hook, making instances of this class
+    /// usable as a `new`-constructor.
+    pub fn construct(mut self, hook: JSNative) -> ClassBuilder {
+        self.ops.construct = hook;
+        self
+    }
+
+    /// Installs the `[[HasInstance]]` hook (the `InterfaceHasInstance`
+    /// pattern), used to back `instanceof` for interface objects whose
+    /// instances aren't plain instances of this class.
+    pub fn has_instance(mut self, hook: JSHasInstanceOp) -> ClassBuilder {
+        self.ops.hasInstance = hook;
+        self
+    }
+
+    /// Leaks the configured class and its ops as `'static` and returns a
+    /// reference to the resulting `JSClass`.
+    pub fn build(self) -> &'static JSClass {
+        unsafe {
+            let ops_ptr = Box::into_raw(Box::new(self.ops));
+            let name_ptr = ffi::CString::into_raw(self.name) as *const ::libc::c_char;
+            let flags = self.flags |
+                ((self.reserved_slots & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT);
+            let class_ptr = Box::into_raw(Box::new(JSClass {
+                name: name_ptr as *const _,
+                flags: flags as u32,
+                cOps: ops_ptr as *const JSClassOps,
+                reserved: [0 as *mut _; 3],
+            }));
+            &*class_ptr
+        }
+    }
+}
+
 static SIMPLE_GLOBAL_CLASS_OPS: JSClassOps = JSClassOps {
     addProperty: None,
     delProperty: None,
@@ -963,4 +1883,178 @@ pub static SIMPLE_GLOBAL_CLASS: JSClass = JSClass {
     flags: (JSCLASS_IS_GLOBAL | ((JSCLASS_GLOBAL_SLOT_COUNT & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT)) as u32,
     cOps: &SIMPLE_GLOBAL_CLASS_OPS as *const JSClassOps,
     reserved: [0 as *mut _; 3]
-};
\ No newline at end of file
+};
+
+// ___________________________________________________________________________
+// Memory reporting and object-graph walking
+
+/// The raw byte counts `glue::CollectServoSizes` fills in, mirroring the
+/// subset of `JS::ServoSizes` (see `MemoryMetrics.h`) this crate cares
+/// about.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ServoSizes {
+    gcHeapUsed: u64,
+    mallocHeap: u64,
+    stringCharsNonHuge: u64,
+    scripts: u64,
+    other: u64,
+}
+
+/// `glue::CollectServoSizes` writes through a `JS::ServoSizes*`, and this
+/// tree doesn't carry the glue bindings needed to confirm that `ServoSizes`
+/// above still matches the real struct's full field list field-for-field.
+/// If upstream has grown fields we don't know about, a bare `ServoSizes`
+/// buffer would be too small and `CollectServoSizes` would write past the
+/// end of it. Pad the actual buffer well past any plausible growth so that
+/// can't happen; only the known `ServoSizes` prefix is ever read back.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ServoSizesBuf {
+    known: ServoSizes,
+    _reserved: [u8; 512],
+}
+
+/// Runtime-wide GC memory statistics, drawn from the same DevTools
+/// `MemoryMetrics.h` accounting `glue::CollectServoSizes` already measures
+/// for `HeapSizeOf`. Each field is a total for the whole runtime; the
+/// counts are not broken down per zone or per realm.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryMetrics {
+    pub gc_heap_bytes: usize,
+    pub malloc_bytes: usize,
+    pub string_bytes: usize,
+    pub script_bytes: usize,
+    pub other_bytes: usize,
+}
+
+impl Runtime {
+    /// Measures this runtime's current GC heap, malloc heap, string, and
+    /// script memory use. Useful for long-running embeddings that need to
+    /// monitor and attribute JS memory usage over time.
+    pub fn memory_metrics(&self) -> MemoryMetrics {
+        unsafe {
+            let mut buf: ServoSizesBuf = mem::zeroed();
+            CollectServoSizes(self.cx, &mut buf as *mut ServoSizesBuf as *mut ServoSizes);
+            let sizes = buf.known;
+            MemoryMetrics {
+                gc_heap_bytes: sizes.gcHeapUsed as usize,
+                malloc_bytes: sizes.mallocHeap as usize,
+                string_bytes: sizes.stringCharsNonHuge as usize,
+                script_bytes: sizes.scripts as usize,
+                other_bytes: sizes.other as usize,
+            }
+        }
+    }
+}
+
+/// An edge out of a node in the graph walked by `HeapGraphIter`: the
+/// property name it's reachable through, and the object it points to.
+pub struct HeapGraphEdge {
+    pub name: String,
+    pub target: *mut JSObject,
+}
+
+/// A node in the graph walked by `HeapGraphIter`: a reachable object and
+/// its outgoing edges.
+pub struct HeapGraphNode {
+    pub object: *mut JSObject,
+    pub edges: Vec<HeapGraphEdge>,
+}
+
+/// A breadth-first walk of the live object graph reachable from a starting
+/// object (typically a global, built with `SIMPLE_GLOBAL_CLASS` or a custom
+/// class from `ClassBuilder`), for building ad hoc heap snapshots for leak
+/// diagnosis. Edges are discovered through the same own-property
+/// enumeration `IdVector` already exposes; this won't see edges the engine
+/// keeps internally (closures' captured scopes, slots on exotic objects),
+/// but covers the common case of walking plain object/array graphs.
+///
+/// Every object this discovers is appended to `seen`, an
+/// `AutoObjectVectorWrapper`, so it stays alive and gets its address
+/// updated by a moving GC for as long as the iterator lives; `pending`
+/// only ever holds indices into `seen`; raw `*mut JSObject` values are
+/// read back out of `seen` (never cached across a call that can run
+/// arbitrary script, such as `JS_GetPropertyById` on an accessor) so
+/// a GC triggered mid-walk can't leave a dangling or stale pointer here.
+pub struct HeapGraphIter {
+    cx: *mut JSContext,
+    seen: AutoObjectVectorWrapper,
+    pending: VecDeque<usize>,
+}
+
+impl HeapGraphIter {
+    fn new(cx: *mut JSContext, start: *mut JSObject) -> HeapGraphIter {
+        let seen = AutoObjectVectorWrapper::new(cx);
+        seen.append(start);
+        let mut pending = VecDeque::new();
+        pending.push_back(0);
+        HeapGraphIter {
+            cx: cx,
+            seen: seen,
+            pending: pending,
+        }
+    }
+}
+
+impl Iterator for HeapGraphIter {
+    type Item = HeapGraphNode;
+
+    fn next(&mut self) -> Option<HeapGraphNode> {
+        let index = match self.pending.pop_front() {
+            Some(index) => index,
+            None => return None,
+        };
+        let object = self.seen[index];
+
+        unsafe {
+            rooted!(in(self.cx) let obj = object);
+            let ids = IdVector::new(self.cx);
+            if !JS_Enumerate(self.cx, obj.handle(), ids.get()) {
+                return Some(HeapGraphNode { object: obj.get(), edges: Vec::new() });
+            }
+
+            // Edge targets are kept as indices into `seen` until the loop is
+            // done, since a later `JS_GetPropertyById` can move them.
+            let mut edge_indices = Vec::new();
+            for &id in ids.iter() {
+                rooted!(in(self.cx) let mut value = jsval::UndefinedValue());
+                if !JS_GetPropertyById(self.cx, obj.handle(), id, value.handle_mut()) {
+                    continue;
+                }
+                if !value.is_object() {
+                    continue;
+                }
+                let target = value.to_object();
+                let name = if JSID_IS_STRING(id) {
+                    js_string_to_string(self.cx, JSID_TO_STRING(id))
+                } else {
+                    format!("{:?}", id)
+                };
+                let target_index = match self.seen.iter().position(|&seen| seen == target) {
+                    Some(seen_index) => seen_index,
+                    None => {
+                        let new_index = self.seen.len();
+                        self.seen.append(target);
+                        self.pending.push_back(new_index);
+                        new_index
+                    }
+                };
+                edge_indices.push((name, target_index));
+            }
+
+            let edges = edge_indices.into_iter()
+                .map(|(name, target_index)| HeapGraphEdge { name: name, target: self.seen[target_index] })
+                .collect();
+            Some(HeapGraphNode { object: obj.get(), edges: edges })
+        }
+    }
+}
+
+impl Runtime {
+    /// Starts a breadth-first walk of the live object graph reachable from
+    /// `start`. See `HeapGraphIter`.
+    pub fn walk_heap_graph(&self, start: JS::HandleObject) -> HeapGraphIter {
+        HeapGraphIter::new(self.cx, start.get())
+    }
+}
\ No newline at end of file